@@ -1,11 +1,19 @@
-use tokio::{net::TcpStream, io::AsyncReadExt, io::AsyncWriteExt};
-use bytes::BytesMut;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use futures::{SinkExt, StreamExt};
+use bytes::{Buf, BytesMut};
 use anyhow::{Result, Error};
 
+/// How many server-initiated pushes (Pub/Sub messages, keyspace
+/// notifications, client-tracking invalidations, ...) a connection will
+/// buffer before a publisher has to wait for `read_value` to catch up.
+const PUSH_CHANNEL_CAPACITY: usize = 64;
+
 const CARRIAGE_RETURN: u8 = '\r' as u8;
 const NEWLINE: u8 = '\n' as u8;
 
-#[derive(Eq, PartialEq, Clone, Debug)]
+#[derive(PartialEq, Clone, Debug)]
 pub enum Value {
     /// For Simple Strings the first byte of the reply is "+".
     String(String),
@@ -15,6 +23,64 @@ pub enum Value {
     Bulk(String),
     /// For Arrays the first byte of the reply is "*".
     Array(Vec<Value>),
+    /// RESP3: for Integers the first byte of the reply is ":".
+    Integer(i64),
+    /// RESP3: the Null type, the first byte of the reply is "_".
+    Null,
+    /// RESP3: for Doubles the first byte of the reply is ",".
+    Double(f64),
+    /// RESP3: for Booleans the first byte of the reply is "#".
+    Boolean(bool),
+    /// RESP3: for Big Numbers the first byte of the reply is "(".
+    BigNumber(String),
+    /// RESP3: for Verbatim Strings the first byte of the reply is "=".
+    VerbatimString(String),
+    /// RESP3: for Maps the first byte of the reply is "%".
+    Map(Vec<(Value, Value)>),
+    /// RESP3: for Sets the first byte of the reply is "~".
+    Set(Vec<Value>),
+    /// RESP3: for Pushes the first byte of the reply is ">".
+    Push(Vec<Value>),
+}
+
+/// Which RESP dialect a connection has negotiated via `HELLO`. Clients stay
+/// on `Resp2` until they ask for `Resp3`, so replies built from the new
+/// RESP3-only variants need to know which wire format to fall back to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ProtocolVersion {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// Bounds on the declared lengths the parser will trust from the wire.
+/// Without these, a header like `$1000000000\r\n` makes `read_value`
+/// buffer without limit before it ever sees the payload is missing, a cheap
+/// OOM for a remote peer to trigger.
+#[derive(Clone, Copy, Debug)]
+pub struct ParserLimits {
+    pub max_bulk_len: i64,
+    pub max_array_len: i64,
+    /// Caps every CRLF-terminated line the parser scans for (simple
+    /// strings, integers, doubles, booleans, big numbers, length headers,
+    /// and inline commands). Without this, a line with no terminator at all
+    /// lets a peer buffer without bound even though none of the
+    /// length-prefixed types are involved.
+    pub max_line_len: usize,
+}
+
+impl Default for ParserLimits {
+    fn default() -> Self {
+        // 512 MiB bulk payloads and 1M aggregate elements, roughly the caps
+        // real Redis and HTTP frame parsers (e.g. the 131072-byte header cap)
+        // use to keep a single frame from exhausting memory. 64 KiB matches
+        // real Redis's PROTO_INLINE_MAX_SIZE for a single line.
+        ParserLimits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: 1_000_000,
+            max_line_len: 64 * 1024,
+        }
+    }
 }
 
 impl Value {
@@ -34,68 +100,328 @@ impl Value {
         }
     }
 
-    pub fn encode(self) -> String {
-        match &self {
+    /// Like `unwrap_bulk`, but for callers (e.g. command-argument parsing)
+    /// that can't trust a peer to send a bulk string and need to return a
+    /// protocol error instead of panicking the connection task.
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Bulk(str) | Value::String(str) => Some(str.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Encode this value for the wire, downgrading RESP3-only variants to
+    /// their RESP2 equivalent when `protocol` is `Resp2` (e.g. `Map` becomes
+    /// a flat `Array`, `Null` becomes `$-1\r\n`).
+    pub fn encode(self, protocol: ProtocolVersion) -> String {
+        match self {
             Value::String(s) => format!("+{}\r\n", s.as_str()),
             Value::Error(msg) => format!("-{}\r\n", msg.as_str()),
-            Value::Bulk(s) => format!("${}\r\n{}\r\n", s.chars().count(), s),
-            // The other cases are not required
-            _ => panic!("value encode not implemented for: {:?}", self)
+            Value::Bulk(s) => format!("${}\r\n{}\r\n", s.len(), s),
+            Value::Array(items) => encode_aggregate('*', items, protocol),
+            Value::Integer(i) => format!(":{}\r\n", i),
+            Value::Null => match protocol {
+                ProtocolVersion::Resp3 => "_\r\n".to_string(),
+                ProtocolVersion::Resp2 => "$-1\r\n".to_string(),
+            },
+            Value::Double(d) => match protocol {
+                ProtocolVersion::Resp3 => format!(",{}\r\n", d),
+                ProtocolVersion::Resp2 => Value::Bulk(d.to_string()).encode(protocol),
+            },
+            Value::Boolean(b) => match protocol {
+                ProtocolVersion::Resp3 => format!("#{}\r\n", if b { 't' } else { 'f' }),
+                ProtocolVersion::Resp2 => Value::Integer(if b { 1 } else { 0 }).encode(protocol),
+            },
+            Value::BigNumber(s) => match protocol {
+                ProtocolVersion::Resp3 => format!("({}\r\n", s),
+                ProtocolVersion::Resp2 => Value::Bulk(s).encode(protocol),
+            },
+            Value::VerbatimString(s) => match protocol {
+                ProtocolVersion::Resp3 => format!("={}\r\ntxt:{}\r\n", s.len() + 4, s),
+                ProtocolVersion::Resp2 => Value::Bulk(s).encode(protocol),
+            },
+            Value::Map(pairs) => match protocol {
+                ProtocolVersion::Resp3 => {
+                    let mut out = format!("%{}\r\n", pairs.len());
+                    for (key, value) in pairs {
+                        out.push_str(&key.encode(protocol));
+                        out.push_str(&value.encode(protocol));
+                    }
+                    out
+                }
+                ProtocolVersion::Resp2 => {
+                    let flattened = pairs.into_iter().flat_map(|(k, v)| vec![k, v]).collect();
+                    encode_aggregate('*', flattened, protocol)
+                }
+            },
+            Value::Set(items) => encode_aggregate(if protocol == ProtocolVersion::Resp3 { '~' } else { '*' }, items, protocol),
+            Value::Push(items) => encode_aggregate(if protocol == ProtocolVersion::Resp3 { '>' } else { '*' }, items, protocol),
         }
     }
 }
 
+fn encode_aggregate(marker: char, items: Vec<Value>, protocol: ProtocolVersion) -> String {
+    let mut out = format!("{}{}\r\n", marker, items.len());
+    for item in items {
+        out.push_str(&item.encode(protocol));
+    }
+    out
+}
+
+/// A `tokio_util` codec that turns a byte stream into a stream/sink of RESP
+/// [`Value`]s. Wrapping any `AsyncRead + AsyncWrite` in `Framed<T, RespCodec>`
+/// gives a `Stream<Item = Result<Value>>` and a `Sink<Value>` for free, so the
+/// connection plumbing (splitting, timeouts, multiplexing) can reuse the rest
+/// of the tokio ecosystem instead of the bespoke read loop this replaces.
+#[derive(Default)]
+pub struct RespCodec {
+    protocol: ProtocolVersion,
+    limits: ParserLimits,
+}
+
+impl Decoder for RespCodec {
+    type Item = Value;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Value>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        match parse_message(&buf[..], &self.limits)? {
+            Some((value, consumed)) => {
+                buf.advance(consumed);
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Value> for RespCodec {
+    type Error = Error;
+
+    fn encode(&mut self, value: Value, dst: &mut BytesMut) -> Result<()> {
+        dst.extend_from_slice(value.encode(self.protocol).as_bytes());
+
+        Ok(())
+    }
+}
+
 pub struct RespConnection {
-    stream: TcpStream,
-    buffer: BytesMut,
+    framed: Framed<TcpStream, RespCodec>,
+    push_sender: mpsc::Sender<Value>,
+    push_receiver: mpsc::Receiver<Value>,
+}
+
+/// A cloneable, out-of-band sender that other tasks use to enqueue
+/// server-initiated [`Value::Push`] payloads (Pub/Sub `message`/`pmessage`,
+/// keyspace notifications, client-tracking invalidations, ...) on a
+/// connection, obtained via [`RespConnection::push_handle`]. NATS calls the
+/// analogous concept an asynchronous server operation: a subject-addressed
+/// message that arrives independently of any request the subscriber sent.
+#[derive(Clone)]
+pub struct PushHandle {
+    sender: mpsc::Sender<Value>,
+}
+
+impl PushHandle {
+    pub async fn push(&self, value: Value) -> Result<()> {
+        self.sender.send(value).await.map_err(|_| Error::msg("connection closed"))
+    }
 }
 
 impl RespConnection {
     pub fn new(stream: TcpStream) -> Self {
+        let (push_sender, push_receiver) = mpsc::channel(PUSH_CHANNEL_CAPACITY);
+
         return RespConnection{
-            stream, 
-            buffer: BytesMut::with_capacity(512),
+            framed: Framed::new(stream, RespCodec::default()),
+            push_sender,
+            push_receiver,
         };
     }
 
+    pub fn push_handle(&self) -> PushHandle {
+        PushHandle { sender: self.push_sender.clone() }
+    }
+
+    /// Reads the next client request, writing out any queued pushes as they
+    /// arrive in the meantime so they can interleave with replies instead of
+    /// waiting behind the next request.
     pub async fn read_value(&mut self) -> Result<Option<Value>> {
         loop {
-            let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
+            tokio::select! {
+                biased;
 
-            if bytes_read == 0 {
-                if self.buffer.is_empty() {
-                    return Ok(None);
-                } else {
-                    return Err(Error::msg("connection closed unexpectedly"));
+                push = self.push_receiver.recv() => {
+                    if let Some(push) = push {
+                        self.framed.send(push).await?;
+                    }
+                }
+                value = self.framed.next() => {
+                    return match value {
+                        Some(value) => value.map(Some),
+                        None => Ok(None),
+                    };
                 }
-            }
-
-            if let Some((value, _)) = parse_message(self.buffer.split())? {
-                return Ok(Some(value));
             }
         }
     }
 
     pub async fn write_value(&mut self, value: Value) -> Result<()> {
-        self.stream.write(value.encode().as_bytes()).await?;
+        self.framed.send(value).await
+    }
 
-        Ok(())
+    pub fn protocol(&self) -> ProtocolVersion {
+        self.framed.codec().protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: ProtocolVersion) {
+        self.framed.codec_mut().protocol = protocol;
+    }
+
+    pub fn limits(&self) -> ParserLimits {
+        self.framed.codec().limits
+    }
+
+    pub fn set_limits(&mut self, limits: ParserLimits) {
+        self.framed.codec_mut().limits = limits;
+    }
+
+    /// Handle a `HELLO [version]` command: negotiates the connection's RESP
+    /// dialect and returns the server info reply for it. A bare `HELLO`
+    /// keeps the connection's current dialect (RESP2 unless it was switched).
+    pub fn negotiate_hello(&mut self, args: &[Value]) -> Result<Value> {
+        let protocol = match args.first() {
+            None => self.protocol(),
+            Some(value) => match value.as_str() {
+                Some("2") => ProtocolVersion::Resp2,
+                Some("3") => ProtocolVersion::Resp3,
+                Some(other) => return Err(Error::msg(format!("NOPROTO unsupported protocol version: {}", other))),
+                None => return Err(Error::msg("NOPROTO unsupported protocol version")),
+            },
+        };
+
+        self.set_protocol(protocol);
+
+        Ok(Value::Map(vec![
+            (Value::Bulk("server".to_string()), Value::Bulk("redis".to_string())),
+            (Value::Bulk("proto".to_string()), Value::Integer(match protocol {
+                ProtocolVersion::Resp2 => 2,
+                ProtocolVersion::Resp3 => 3,
+            })),
+        ]))
     }
 }
 
-fn parse_message(buffer: BytesMut) -> Result<Option<(Value, usize)>> {
+// The decode_* functions below all take a borrowed `&[u8]` rather than an
+// owned buffer and return the number of bytes consumed instead of a new
+// buffer. This keeps nested parsing (e.g. array elements) to a single pass
+// over the input with no intermediate copies, which matters once pipelined
+// arrays get large: the old `BytesMut::from(&buffer[consumed..])` per
+// element made array decoding O(n^2) in the number of elements.
+fn parse_message(buffer: &[u8], limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    if buffer.is_empty() {
+        return Ok(None);
+    }
+
     match buffer[0] as char {
-        '+' => decode_simple_string(buffer),
-        '*' => decode_array(buffer),
-        '$' => decode_bulk_string(buffer),
-        _ => {
-            return Err(Error::msg("unrecognised message type"));
+        '+' => decode_simple_string(buffer, limits),
+        '*' => decode_aggregate(buffer, '*', limits),
+        '$' => decode_bulk_string(buffer, limits),
+        ':' => decode_integer(buffer, limits),
+        '_' => decode_null(buffer, limits),
+        ',' => decode_double(buffer, limits),
+        '#' => decode_boolean(buffer, limits),
+        '(' => decode_big_number(buffer, limits),
+        '=' => decode_verbatim_string(buffer, limits),
+        '%' => decode_map(buffer, limits),
+        '~' => decode_aggregate(buffer, '~', limits),
+        '>' => decode_aggregate(buffer, '>', limits),
+        _ => decode_inline_command(buffer, limits),
+    }
+}
+
+/// Plain `redis-cli`/`telnet` usage sends commands as a bare line (e.g.
+/// `PING\r\n`) rather than a typed RESP array. Blank lines are ignored, as
+/// real Redis does, so a client idly pressing enter doesn't desync the
+/// parser.
+fn decode_inline_command(buffer: &[u8], limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    let mut bytes_consumed = 0;
+
+    loop {
+        let (line, len) = match get_line(&buffer[bytes_consumed..], limits.max_line_len)? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        bytes_consumed += len;
+
+        let tokens = split_inline_args(parse_string(line)?.as_str())?;
+        if tokens.is_empty() {
+            continue;
         }
+
+        let items = tokens.into_iter().map(Value::Bulk).collect();
+
+        return Ok(Some((Value::Array(items), bytes_consumed)));
     }
 }
 
-fn decode_simple_string(buffer: BytesMut) -> Result<Option<(Value, usize)>> {
-    if let Some((line, len)) = get_line(&buffer[1..]) {
+/// Splits an inline command line on whitespace, treating a `"..."` or
+/// `'...'` run as a single argument (with `\`-escaping inside double
+/// quotes) the way `redis-cli` lets you pass a value containing spaces.
+fn split_inline_args(line: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let mut token = String::new();
+
+        if c == '"' || c == '\'' {
+            let quote = c;
+            chars.next();
+            let mut closed = false;
+
+            while let Some(c) = chars.next() {
+                if c == '\\' && quote == '"' {
+                    if let Some(escaped) = chars.next() {
+                        token.push(escaped);
+                    }
+                } else if c == quote {
+                    closed = true;
+                    break;
+                } else {
+                    token.push(c);
+                }
+            }
+
+            if !closed {
+                return Err(Error::msg("unbalanced quotes in inline command"));
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+        }
+
+        args.push(token);
+    }
+
+    Ok(args)
+}
+
+fn decode_simple_string(buffer: &[u8], limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    if let Some((line, len)) = get_line(&buffer[1..], limits.max_line_len)? {
         let str = parse_string(line)?;
 
         Ok(Some((Value::String(str), len + 1)))
@@ -104,18 +430,31 @@ fn decode_simple_string(buffer: BytesMut) -> Result<Option<(Value, usize)>> {
     }
 }
 
-fn decode_array(buffer: BytesMut) -> Result<Option<(Value, usize)>> {
-    let (array_length, mut bytes_consumed) = if let Some((line, len)) = get_line(&buffer[1..]) {
-        let array_length = parse_integer(line)?;
+/// Decodes the three RESP3 aggregate types that only differ in their marker
+/// byte and the `Value` variant they produce: `*` arrays, `~` sets and `>`
+/// pushes.
+fn decode_aggregate(buffer: &[u8], marker: char, limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    let (length, mut bytes_consumed) = if let Some((line, len)) = get_line(&buffer[1..], limits.max_line_len)? {
+        let length = parse_integer(line)?;
 
-        (array_length, len + 1)
+        (length, len + 1)
     } else {
         return Ok(None);
     };
 
+    if length == -1 {
+        return Ok(Some((Value::Null, bytes_consumed)));
+    }
+    if length < -1 {
+        return Err(Error::msg(format!("invalid aggregate length: {}", length)));
+    }
+    if length > limits.max_array_len {
+        return Err(Error::msg(format!("aggregate length {} exceeds configured maximum of {}", length, limits.max_array_len)));
+    }
+
     let mut items: Vec<Value> = Vec::new();
-    for _ in 0..array_length {
-        if let Some((v, len)) = parse_message(BytesMut::from(&buffer[bytes_consumed..]))? {
+    for _ in 0..length {
+        if let Some((v, len)) = parse_message(&buffer[bytes_consumed..], limits)? {
             items.push(v);
             bytes_consumed += len
         } else {
@@ -123,11 +462,17 @@ fn decode_array(buffer: BytesMut) -> Result<Option<(Value, usize)>> {
         }
     }
 
-    return Ok(Some((Value::Array(items), bytes_consumed)));
+    let value = match marker {
+        '~' => Value::Set(items),
+        '>' => Value::Push(items),
+        _ => Value::Array(items),
+    };
+
+    return Ok(Some((value, bytes_consumed)));
 }
 
-fn decode_bulk_string(buffer: BytesMut) -> Result<Option<(Value, usize)>> {
-    let (bulk_length, bytes_consumed) = if let Some((line, len)) = get_line(&buffer[1..]) {
+fn decode_bulk_string(buffer: &[u8], limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    let (bulk_length, bytes_consumed) = if let Some((line, len)) = get_line(&buffer[1..], limits.max_line_len)? {
         let bulk_length = parse_integer(line)?;
 
         (bulk_length, len + 1)
@@ -135,6 +480,16 @@ fn decode_bulk_string(buffer: BytesMut) -> Result<Option<(Value, usize)>> {
         return Ok(None);
     };
 
+    if bulk_length == -1 {
+        return Ok(Some((Value::Null, bytes_consumed)));
+    }
+    if bulk_length < -1 {
+        return Err(Error::msg(format!("invalid bulk string length: {}", bulk_length)));
+    }
+    if bulk_length > limits.max_bulk_len {
+        return Err(Error::msg(format!("bulk string length {} exceeds configured maximum of {}", bulk_length, limits.max_bulk_len)));
+    }
+
     let end_of_bulk = bytes_consumed + (bulk_length as usize);
     let end_of_bulk_line = end_of_bulk + 2;
 
@@ -145,14 +500,141 @@ fn decode_bulk_string(buffer: BytesMut) -> Result<Option<(Value, usize)>> {
     };
 }
 
-fn get_line(buffer: &[u8]) -> Option<(&[u8], usize)> {
-    for i in 1..buffer.len() {
+fn decode_integer(buffer: &[u8], limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    if let Some((line, len)) = get_line(&buffer[1..], limits.max_line_len)? {
+        Ok(Some((Value::Integer(parse_integer(line)?), len + 1)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn decode_null(buffer: &[u8], limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    if let Some((line, len)) = get_line(&buffer[1..], limits.max_line_len)? {
+        if !line.is_empty() {
+            return Err(Error::msg("malformed null"));
+        }
+
+        Ok(Some((Value::Null, len + 1)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn decode_double(buffer: &[u8], limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    if let Some((line, len)) = get_line(&buffer[1..], limits.max_line_len)? {
+        let str = parse_string(line)?;
+        let double = str.parse::<f64>().map_err(|_| Error::msg("Could not parse double"))?;
+
+        Ok(Some((Value::Double(double), len + 1)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn decode_boolean(buffer: &[u8], limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    if let Some((line, len)) = get_line(&buffer[1..], limits.max_line_len)? {
+        let boolean = match line {
+            b"t" => true,
+            b"f" => false,
+            _ => return Err(Error::msg("malformed boolean")),
+        };
+
+        Ok(Some((Value::Boolean(boolean), len + 1)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn decode_big_number(buffer: &[u8], limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    if let Some((line, len)) = get_line(&buffer[1..], limits.max_line_len)? {
+        Ok(Some((Value::BigNumber(parse_string(line)?), len + 1)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn decode_verbatim_string(buffer: &[u8], limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    let (payload_length, bytes_consumed) = if let Some((line, len)) = get_line(&buffer[1..], limits.max_line_len)? {
+        (parse_integer(line)?, len + 1)
+    } else {
+        return Ok(None);
+    };
+
+    if payload_length < 0 {
+        return Err(Error::msg(format!("invalid verbatim string length: {}", payload_length)));
+    }
+    if payload_length > limits.max_bulk_len {
+        return Err(Error::msg(format!("verbatim string length {} exceeds configured maximum of {}", payload_length, limits.max_bulk_len)));
+    }
+
+    let payload_length = payload_length as usize;
+    let end_of_payload = bytes_consumed + payload_length;
+    let end_of_line = end_of_payload + 2;
+
+    if end_of_line > buffer.len() {
+        return Ok(None);
+    }
+
+    // Verbatim strings carry a 3-byte format tag (e.g. "txt") plus a colon
+    // ahead of the actual text, which callers don't need to see.
+    let payload = parse_string(&buffer[bytes_consumed..end_of_payload])?;
+    let text = payload.get(4..).unwrap_or("").to_string();
+
+    Ok(Some((Value::VerbatimString(text), end_of_line)))
+}
+
+fn decode_map(buffer: &[u8], limits: &ParserLimits) -> Result<Option<(Value, usize)>> {
+    let (pair_count, mut bytes_consumed) = if let Some((line, len)) = get_line(&buffer[1..], limits.max_line_len)? {
+        (parse_integer(line)?, len + 1)
+    } else {
+        return Ok(None);
+    };
+
+    if pair_count < 0 {
+        return Err(Error::msg(format!("invalid map length: {}", pair_count)));
+    }
+    if pair_count > limits.max_array_len {
+        return Err(Error::msg(format!("map length {} exceeds configured maximum of {}", pair_count, limits.max_array_len)));
+    }
+
+    let mut pairs: Vec<(Value, Value)> = Vec::new();
+    for _ in 0..pair_count {
+        let key = if let Some((v, len)) = parse_message(&buffer[bytes_consumed..], limits)? {
+            bytes_consumed += len;
+            v
+        } else {
+            return Ok(None);
+        };
+
+        let value = if let Some((v, len)) = parse_message(&buffer[bytes_consumed..], limits)? {
+            bytes_consumed += len;
+            v
+        } else {
+            return Ok(None);
+        };
+
+        pairs.push((key, value));
+    }
+
+    return Ok(Some((Value::Map(pairs), bytes_consumed)));
+}
+
+// Caps how far we scan for a CRLF so a line with no terminator can't make us
+// buffer an unbounded amount of data, the way real Redis bounds inline
+// commands at PROTO_INLINE_MAX_SIZE.
+fn get_line(buffer: &[u8], max_len: usize) -> Result<Option<(&[u8], usize)>> {
+    let scan_len = buffer.len().min(max_len + 2);
+    for i in 1..scan_len {
         if buffer[i - 1] == CARRIAGE_RETURN && buffer[i] == NEWLINE {
-            return Some((&buffer[0..(i - 1)], i + 1));
+            return Ok(Some((&buffer[0..(i - 1)], i + 1)));
         }
     }
 
-    return None;
+    if buffer.len() > max_len {
+        return Err(Error::msg(format!("line exceeds maximum allowed length of {} bytes", max_len)));
+    }
+
+    Ok(None)
 }
 
 fn parse_string(bytes: &[u8]) -> Result<String> {
@@ -170,14 +652,14 @@ mod tests {
 
     #[test]
     fn parse_ping_message() {
-        let result = parse_message(BytesMut::from("+PING\r\n")).unwrap().map(|out| out.0).unwrap();
+        let result = parse_message("+PING\r\n".as_bytes(), &ParserLimits::default()).unwrap().map(|out| out.0).unwrap();
 
         assert_eq!(Value::String("PING".to_string()), result);
     }
 
     #[test]
     fn parse_array_of_ping_message() {
-        let result = parse_message(BytesMut::from("*1\r\n$4\r\nping\r\n")).unwrap().map(|out| out.0).unwrap();
+        let result = parse_message("*1\r\n$4\r\nping\r\n".as_bytes(), &ParserLimits::default()).unwrap().map(|out| out.0).unwrap();
 
         let command = Value::Bulk("ping".to_string());
         assert_eq!(Value::Array(vec![command]), result);
@@ -185,10 +667,247 @@ mod tests {
 
     #[test]
     fn parse_echo_message() {
-        let result = parse_message(BytesMut::from("*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n")).unwrap().map(|out| out.0).unwrap();
+        let result = parse_message("*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n".as_bytes(), &ParserLimits::default()).unwrap().map(|out| out.0).unwrap();
 
         let command = Value::Bulk("ECHO".to_string());
         let arg = Value::Bulk("hey".to_string());
         assert_eq!(Value::Array(vec![command, arg]), result);
     }
+
+    #[test]
+    fn parse_large_mset_array_in_a_single_pass() {
+        // MSET key0 val0 key1 val1 ... key999 val999, encoded as a flat
+        // RESP array. Walking this should not require copying the
+        // remaining buffer once per element (see decode_aggregate above).
+        let pair_count = 1000;
+        let mut message = format!("*{}\r\n$4\r\nMSET\r\n", pair_count * 2 + 1);
+        for i in 0..pair_count {
+            let key = format!("key{}", i);
+            let val = format!("val{}", i);
+            message.push_str(&format!("${}\r\n{}\r\n", key.len(), key));
+            message.push_str(&format!("${}\r\n{}\r\n", val.len(), val));
+        }
+
+        let (result, consumed) = parse_message(message.as_bytes(), &ParserLimits::default()).unwrap().unwrap();
+
+        assert_eq!(consumed, message.len());
+        match result {
+            Value::Array(items) => assert_eq!(items.len(), pair_count * 2 + 1),
+            other => panic!("expected an array, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_resp3_scalar_types() {
+        assert_eq!(Value::Integer(42), parse_message(":42\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap().0);
+        assert_eq!(Value::Null, parse_message("_\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap().0);
+        assert_eq!(Value::Double(2.5), parse_message(",2.5\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap().0);
+        assert_eq!(Value::Boolean(true), parse_message("#t\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap().0);
+        assert_eq!(Value::BigNumber("3492890328409238509324850943850943825024385".to_string()), parse_message("(3492890328409238509324850943850943825024385\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap().0);
+        assert_eq!(Value::VerbatimString("Some string".to_string()), parse_message("=15\r\ntxt:Some string\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap().0);
+    }
+
+    #[test]
+    fn parse_resp3_map_and_set() {
+        let map = parse_message("%1\r\n$3\r\nfoo\r\n$3\r\nbar\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap().0;
+        assert_eq!(Value::Map(vec![(Value::Bulk("foo".to_string()), Value::Bulk("bar".to_string()))]), map);
+
+        let set = parse_message("~2\r\n:1\r\n:2\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap().0;
+        assert_eq!(Value::Set(vec![Value::Integer(1), Value::Integer(2)]), set);
+    }
+
+    #[test]
+    fn encode_map_downgrades_to_flat_array_on_resp2() {
+        let map = Value::Map(vec![(Value::Bulk("foo".to_string()), Value::Bulk("bar".to_string()))]);
+
+        assert_eq!("*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n", map.clone().encode(ProtocolVersion::Resp2));
+        assert_eq!("%1\r\n$3\r\nfoo\r\n$3\r\nbar\r\n", map.encode(ProtocolVersion::Resp3));
+    }
+
+    #[test]
+    fn encode_verbatim_string_counts_payload_bytes_not_chars() {
+        // "héllo" is 5 chars but 6 UTF-8 bytes; the declared length must
+        // cover the bytes actually written so the next value on the wire
+        // doesn't get misaligned.
+        let encoded = Value::VerbatimString("héllo".to_string()).encode(ProtocolVersion::Resp3);
+
+        assert_eq!("=10\r\ntxt:héllo\r\n", encoded);
+    }
+
+    #[test]
+    fn encode_bulk_counts_payload_bytes_not_chars() {
+        // Same UTF-8 pitfall as the verbatim string case above: "héllo" is
+        // 5 chars but 6 bytes on the wire.
+        let encoded = Value::Bulk("héllo".to_string()).encode(ProtocolVersion::Resp3);
+
+        assert_eq!("$6\r\nhéllo\r\n", encoded);
+    }
+
+    #[test]
+    fn encode_map_downgrade_counts_payload_bytes_not_chars() {
+        // Map downgrades to a flat Array of its keys and values on Resp2, so
+        // a Bulk key goes through the same Value::Bulk(..).encode(...) path.
+        let map = Value::Map(vec![(Value::Bulk("héllo".to_string()), Value::Integer(1))]);
+
+        assert_eq!("*2\r\n$6\r\nhéllo\r\n:1\r\n", map.encode(ProtocolVersion::Resp2));
+    }
+
+    #[test]
+    fn encode_null_downgrades_to_bulk_null_on_resp2() {
+        assert_eq!("$-1\r\n", Value::Null.encode(ProtocolVersion::Resp2));
+        assert_eq!("_\r\n", Value::Null.encode(ProtocolVersion::Resp3));
+    }
+
+    #[tokio::test]
+    async fn negotiate_hello_rejects_a_non_bulk_version_instead_of_panicking() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client_stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (server_stream, _) = listener.accept().await.unwrap();
+        drop(client_stream);
+
+        let mut server = RespConnection::new(server_stream);
+
+        assert!(server.negotiate_hello(&[Value::Integer(3)]).is_err());
+        assert_eq!(ProtocolVersion::Resp2, server.protocol());
+    }
+
+    #[test]
+    fn parse_inline_ping() {
+        let result = parse_message("PING\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap().0;
+
+        assert_eq!(Value::Array(vec![Value::Bulk("PING".to_string())]), result);
+    }
+
+    #[test]
+    fn parse_inline_command_with_quoted_argument() {
+        let result = parse_message("SET key \"hello world\"\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap().0;
+
+        assert_eq!(Value::Array(vec![
+            Value::Bulk("SET".to_string()),
+            Value::Bulk("key".to_string()),
+            Value::Bulk("hello world".to_string()),
+        ]), result);
+    }
+
+    #[test]
+    fn parse_inline_command_skips_leading_blank_lines() {
+        let result = parse_message("\r\n\r\nPING\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap();
+
+        assert_eq!(Value::Array(vec![Value::Bulk("PING".to_string())]), result.0);
+        assert_eq!("\r\n\r\nPING\r\n".len(), result.1);
+    }
+
+    #[test]
+    fn parse_inline_command_skips_whitespace_only_lines() {
+        // A line of only spaces is empty post-split even though its raw
+        // bytes aren't, and must be skipped the same as a truly blank line
+        // rather than producing a bogus empty command.
+        let result = parse_message(" \r\nPING\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap();
+
+        assert_eq!(Value::Array(vec![Value::Bulk("PING".to_string())]), result.0);
+        assert_eq!(" \r\nPING\r\n".len(), result.1);
+    }
+
+    #[test]
+    fn decode_bulk_string_rejects_a_header_over_the_configured_limit() {
+        let limits = ParserLimits { max_bulk_len: 1024, ..ParserLimits::default() };
+
+        let result = parse_message("$1000000000\r\n".as_bytes(), &limits);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn decode_array_rejects_a_header_over_the_configured_limit() {
+        let limits = ParserLimits { max_array_len: 1024, ..ParserLimits::default() };
+
+        let result = parse_message("*1000000000\r\n".as_bytes(), &limits);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn decode_bulk_string_rejects_negative_lengths_other_than_null() {
+        let result = parse_message("$-2\r\n".as_bytes(), &ParserLimits::default());
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn decode_bulk_string_null_decodes_to_value_null() {
+        let result = parse_message("$-1\r\n".as_bytes(), &ParserLimits::default()).unwrap().unwrap();
+
+        assert_eq!(Value::Null, result.0);
+        assert_eq!("$-1\r\n".len(), result.1);
+    }
+
+    #[test]
+    fn parse_truncated_array_awaits_more_bytes_instead_of_panicking() {
+        // The header promises 2 elements but only the first has arrived,
+        // which is an ordinary TCP-fragmentation case, not just malformed
+        // input.
+        let result = parse_message("*2\r\n$4\r\nping\r\n".as_bytes(), &ParserLimits::default()).unwrap();
+
+        assert_eq!(None, result);
+    }
+
+    #[test]
+    fn parse_truncated_map_awaits_more_bytes_instead_of_panicking() {
+        let result = parse_message("%2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n".as_bytes(), &ParserLimits::default()).unwrap();
+
+        assert_eq!(None, result);
+    }
+
+    #[test]
+    fn parse_rejects_a_line_with_no_terminator_beyond_max_line_len() {
+        // A peer that never sends a CRLF must not be able to make us buffer
+        // without bound; once we've scanned past max_line_len bytes looking
+        // for one, give up instead of awaiting more input forever.
+        let limits = ParserLimits { max_line_len: 16, ..ParserLimits::default() };
+        let mut framed = vec![b'+'];
+        framed.extend(vec![b'x'; 32]);
+
+        let result = parse_message(&framed, &limits);
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn push_is_delivered_without_a_preceding_request() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client_stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (server_stream, _) = listener.accept().await.unwrap();
+
+        let mut client = RespConnection::new(client_stream);
+        let mut server = RespConnection::new(server_stream);
+
+        let subscribe = Value::Array(vec![Value::Bulk("SUBSCRIBE".to_string()), Value::Bulk("news".to_string())]);
+        client.write_value(subscribe.clone()).await.unwrap();
+        assert_eq!(subscribe, server.read_value().await.unwrap().unwrap());
+
+        // A RESP3 client would have negotiated this via `HELLO 3`; doing it
+        // directly here keeps the push framed as `>` instead of downgrading
+        // to a plain array.
+        server.set_protocol(ProtocolVersion::Resp3);
+
+        let push_handle = server.push_handle();
+        let message = Value::Push(vec![
+            Value::Bulk("message".to_string()),
+            Value::Bulk("news".to_string()),
+            Value::Bulk("hello".to_string()),
+        ]);
+        push_handle.push(message.clone()).await.unwrap();
+
+        // Nothing else will arrive from the client, so this stays parked
+        // draining the push queue onto the wire for the rest of the test.
+        tokio::spawn(async move {
+            let _ = server.read_value().await;
+        });
+
+        assert_eq!(message, client.read_value().await.unwrap().unwrap());
+    }
 }